@@ -6,28 +6,53 @@ use turbopack_core::reference_type::{ReferenceType, UrlReferenceSubType};
 use turbopack_ecmascript::{
     EcmascriptInputTransform, EcmascriptInputTransformsVc, NextJsPageExportFilter,
 };
+use turbopack_env::ProcessEnvVc;
 
 use super::context::SharedContextType;
 use crate::{
     next_client::context::ClientContextType,
+    next_config::NextConfigVc,
     next_server::context::{PageSsrType, ServerContextType},
 };
 
 /// Returns a list of module rules which apply Next.js-specific transforms.
-pub async fn get_next_transforms_rules(context_ty: SharedContextType) -> Result<Vec<ModuleRule>> {
+pub async fn get_next_transforms_rules(
+    context_ty: SharedContextType,
+    next_config: NextConfigVc,
+    env: ProcessEnvVc,
+) -> Result<Vec<ModuleRule>> {
     let mut rules = vec![];
 
+    if let Some(relay_rule) = get_next_relay_transform_rule(next_config).await? {
+        rules.push(relay_rule);
+    }
+
+    if let Some(modularize_imports_rule) = get_next_modularize_imports_rule(next_config).await? {
+        rules.push(modularize_imports_rule);
+    }
+
+    if let Some(cjs_optimizer_rule) = get_next_cjs_optimizer_rule(next_config).await? {
+        rules.push(cjs_optimizer_rule);
+    }
+
     match context_ty {
         SharedContextType::Server(ServerContextType::Pages {
             pages_dir,
             ssr_type,
         }) => {
+            let page_match = get_next_pages_dir_match_condition(pages_dir).await?;
+
             rules.push(get_next_dynamic_transform_rule(
                 true,
                 true,
                 false,
                 Some(pages_dir),
             ));
+            rules.push(get_next_disallow_export_all_in_page_rule(
+                page_match.clone(),
+            ));
+            rules.push(get_next_page_config_rule(page_match.clone()));
+            rules.push(get_next_amp_attr_rule(page_match));
 
             match ssr_type {
                 PageSsrType::Ssr => {}
@@ -42,43 +67,228 @@ pub async fn get_next_transforms_rules(context_ty: SharedContextType) -> Result<
                 }
             }
         }
-        SharedContextType::Server(ServerContextType::AppSSR { .. }) => {
+        SharedContextType::Server(ServerContextType::AppSSR { app_dir }) => {
             rules.push(get_next_dynamic_transform_rule(true, true, false, None));
+
+            let page_match = get_next_app_dir_match_condition(app_dir).await?;
+            rules.push(get_next_disallow_export_all_in_page_rule(
+                page_match.clone(),
+            ));
+            rules.push(get_next_page_config_rule(page_match));
         }
-        SharedContextType::Server(ServerContextType::AppRSC { .. }) => {
+        SharedContextType::Server(ServerContextType::AppRSC { app_dir }) => {
             rules.push(get_next_dynamic_transform_rule(true, true, true, None));
+
+            let page_match = get_next_app_dir_match_condition(app_dir).await?;
+            rules.push(get_next_disallow_export_all_in_page_rule(
+                page_match.clone(),
+            ));
+            rules.push(get_next_page_config_rule(page_match));
         }
-        SharedContextType::Client(client_context_type) => {
-            rules.push(get_next_font_transform_rule());
+        SharedContextType::Client(client_context_type) => match client_context_type {
+            ClientContextType::Pages { pages_dir } => {
+                let page_match = get_next_pages_dir_match_condition(pages_dir).await?;
 
-            match client_context_type {
-                ClientContextType::Pages { pages_dir } => {
-                    rules.push(
-                        get_next_pages_transforms_rule(
-                            pages_dir,
-                            NextJsPageExportFilter::StripDataExports,
-                        )
-                        .await?,
-                    );
-                    rules.push(get_next_dynamic_transform_rule(
-                        true,
-                        false,
-                        false,
-                        Some(pages_dir),
-                    ));
-                }
-                ClientContextType::App { .. }
-                | ClientContextType::Fallback
-                | ClientContextType::Other => {
-                    rules.push(get_next_dynamic_transform_rule(true, false, false, None));
-                }
+                rules.push(get_next_font_transform_rule(page_match.clone(), env).await?);
+                rules.push(
+                    get_next_pages_transforms_rule(
+                        pages_dir,
+                        NextJsPageExportFilter::StripDataExports,
+                    )
+                    .await?,
+                );
+                rules.push(get_next_dynamic_transform_rule(
+                    true,
+                    false,
+                    false,
+                    Some(pages_dir),
+                ));
+                rules.push(get_next_disallow_export_all_in_page_rule(
+                    page_match.clone(),
+                ));
+                rules.push(get_next_page_config_rule(page_match.clone()));
+                rules.push(get_next_amp_attr_rule(page_match));
             }
-        }
+            ClientContextType::App { app_dir } => {
+                let page_match = get_next_app_dir_match_condition(app_dir).await?;
+
+                rules.push(get_next_font_transform_rule(page_match.clone(), env).await?);
+                rules.push(get_next_dynamic_transform_rule(true, false, false, None));
+                rules.push(get_next_disallow_export_all_in_page_rule(
+                    page_match.clone(),
+                ));
+                rules.push(get_next_page_config_rule(page_match));
+            }
+            ClientContextType::Fallback | ClientContextType::Other => {
+                rules.push(get_next_dynamic_transform_rule(true, false, false, None));
+            }
+        },
     }
 
+    // User-provided Wasm SWC plugins run last, after all of the built-in Next.js
+    // transforms above have had a chance to run.
+    rules.extend(get_next_swc_plugin_rules(next_config).await?);
+
     Ok(rules)
 }
 
+/// Returns a rule which rewrites named imports of configured packages (e.g.
+/// `lodash`) into per-member imports, so bundlers only pull in the members
+/// that are actually used, per `experimental.modularizeImports`. Applies to
+/// every server and client context, since the rewrite is purely syntactic.
+async fn get_next_modularize_imports_rule(
+    next_config: NextConfigVc,
+) -> Result<Option<ModuleRule>> {
+    let packages = &*next_config.modularize_imports_config().await?;
+
+    if packages.is_empty() {
+        return Ok(None);
+    }
+
+    let modularize_imports_transform =
+        EcmascriptInputTransform::NextJsModularizeImports(packages.clone());
+
+    Ok(Some(ModuleRule::new(
+        ModuleRuleCondition::all(vec![
+            ModuleRuleCondition::not(ModuleRuleCondition::ReferenceType(ReferenceType::Url(
+                UrlReferenceSubType::Undefined,
+            ))),
+            ModuleRuleCondition::any(vec![
+                ModuleRuleCondition::ResourcePathEndsWith(".js".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".jsx".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".ts".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".tsx".to_string()),
+            ]),
+        ]),
+        vec![ModuleRuleEffect::AddEcmascriptTransforms(
+            EcmascriptInputTransformsVc::cell(vec![modularize_imports_transform]),
+        )],
+    )))
+}
+
+/// Renames React-style AMP props on JSX elements to their valid HTML form
+/// (e.g. `className` -> `class`, `htmlFor` -> `for`) so AMP pages pass AMP
+/// validation. Gated to page files via `page_match`, since AMP is a
+/// Pages-router-only feature.
+fn get_next_amp_attr_rule(page_match: ModuleRuleCondition) -> ModuleRule {
+    ModuleRule::new(
+        ModuleRuleCondition::all(vec![
+            page_match,
+            ModuleRuleCondition::any(vec![
+                ModuleRuleCondition::ResourcePathEndsWith(".js".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".jsx".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".ts".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".tsx".to_string()),
+            ]),
+        ]),
+        vec![ModuleRuleEffect::AddEcmascriptTransforms(
+            EcmascriptInputTransformsVc::cell(vec![
+                EcmascriptInputTransform::NextJsAmpAttributes,
+            ]),
+        )],
+    )
+}
+
+/// Turns member access on a `require()`d CommonJS module (e.g.
+/// `require("pkg").Foo`) into a direct deep require (`require("pkg/Foo")`),
+/// so an entire CJS package isn't pulled in just to use one export. Applies
+/// across every server and client context, per
+/// `experimental.optimizePackageImports`-style CJS optimization config.
+async fn get_next_cjs_optimizer_rule(next_config: NextConfigVc) -> Result<Option<ModuleRule>> {
+    let packages = &*next_config.cjs_optimizer_config().await?;
+
+    if packages.is_empty() {
+        return Ok(None);
+    }
+
+    let cjs_optimizer_transform = EcmascriptInputTransform::NextJsCjsOptimizer(packages.clone());
+
+    Ok(Some(ModuleRule::new(
+        ModuleRuleCondition::all(vec![
+            ModuleRuleCondition::not(ModuleRuleCondition::ReferenceType(ReferenceType::Url(
+                UrlReferenceSubType::Undefined,
+            ))),
+            ModuleRuleCondition::any(vec![
+                ModuleRuleCondition::ResourcePathEndsWith(".js".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".jsx".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".ts".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".tsx".to_string()),
+            ]),
+        ]),
+        vec![ModuleRuleEffect::AddEcmascriptTransforms(
+            EcmascriptInputTransformsVc::cell(vec![cjs_optimizer_transform]),
+        )],
+    )))
+}
+
+/// Returns one [ModuleRule] per Wasm SWC plugin configured via
+/// `experimental.swcPlugins`, each applying the plugin to `.js/.jsx/.ts/.tsx`
+/// modules across every server and client context. `path` is a
+/// [FileSystemPathVc] rather than a plain string, so turbo-tasks reads the
+/// plugin's Wasm bytes as a tracked input: editing the binary changes its
+/// content hash and invalidates every module the plugin was applied to.
+async fn get_next_swc_plugin_rules(next_config: NextConfigVc) -> Result<Vec<ModuleRule>> {
+    let plugins = &*next_config.swc_plugins().await?;
+
+    let mut rules = Vec::with_capacity(plugins.len());
+
+    for (path, plugin_config) in plugins {
+        let plugin_transform = EcmascriptInputTransform::SwcPlugin {
+            path: *path,
+            config: plugin_config.clone(),
+        };
+
+        rules.push(ModuleRule::new(
+            ModuleRuleCondition::all(vec![
+                ModuleRuleCondition::not(ModuleRuleCondition::ReferenceType(ReferenceType::Url(
+                    UrlReferenceSubType::Undefined,
+                ))),
+                ModuleRuleCondition::any(vec![
+                    ModuleRuleCondition::ResourcePathEndsWith(".js".to_string()),
+                    ModuleRuleCondition::ResourcePathEndsWith(".jsx".to_string()),
+                    ModuleRuleCondition::ResourcePathEndsWith(".ts".to_string()),
+                    ModuleRuleCondition::ResourcePathEndsWith(".tsx".to_string()),
+                ]),
+            ]),
+            vec![ModuleRuleEffect::AddEcmascriptTransforms(
+                EcmascriptInputTransformsVc::cell(vec![plugin_transform]),
+            )],
+        ));
+    }
+
+    Ok(rules)
+}
+
+/// Returns a rule which rewrites `graphql` tagged template literals into
+/// `require()`s of their generated Relay artifacts, when the user has opted
+/// in via `compiler.relay` in their Next config. Takes the `next_config`
+/// passed into [get_next_transforms_rules] directly, so it applies uniformly
+/// to Pages and App, server and client, regardless of [SharedContextType].
+async fn get_next_relay_transform_rule(next_config: NextConfigVc) -> Result<Option<ModuleRule>> {
+    let Some(relay_config) = &*next_config.relay_config().await? else {
+        return Ok(None);
+    };
+
+    let relay_transform = EcmascriptInputTransform::NextJsRelay(relay_config.clone());
+
+    Ok(Some(ModuleRule::new(
+        ModuleRuleCondition::all(vec![
+            ModuleRuleCondition::not(ModuleRuleCondition::ReferenceType(ReferenceType::Url(
+                UrlReferenceSubType::Undefined,
+            ))),
+            ModuleRuleCondition::any(vec![
+                ModuleRuleCondition::ResourcePathEndsWith(".js".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".jsx".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".ts".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".tsx".to_string()),
+            ]),
+        ]),
+        vec![ModuleRuleEffect::AddEcmascriptTransforms(
+            EcmascriptInputTransformsVc::cell(vec![relay_transform]),
+        )],
+    )))
+}
+
 async fn get_next_pages_transforms_rule(
     pages_dir: FileSystemPathVc,
     export_filter: NextJsPageExportFilter,
@@ -87,19 +297,7 @@ async fn get_next_pages_transforms_rule(
     let strip_transform = EcmascriptInputTransform::NextJsStripPageExports(export_filter);
     Ok(ModuleRule::new(
         ModuleRuleCondition::all(vec![
-            ModuleRuleCondition::all(vec![
-                ModuleRuleCondition::ResourcePathInExactDirectory(pages_dir.await?),
-                ModuleRuleCondition::not(ModuleRuleCondition::ResourcePathInExactDirectory(
-                    pages_dir.join("api").await?,
-                )),
-                ModuleRuleCondition::not(ModuleRuleCondition::any(vec![
-                    // TODO(alexkirsz): Possibly ignore _app as well?
-                    ModuleRuleCondition::ResourcePathEquals(pages_dir.join("_document.js").await?),
-                    ModuleRuleCondition::ResourcePathEquals(pages_dir.join("_document.jsx").await?),
-                    ModuleRuleCondition::ResourcePathEquals(pages_dir.join("_document.ts").await?),
-                    ModuleRuleCondition::ResourcePathEquals(pages_dir.join("_document.tsx").await?),
-                ])),
-            ]),
+            get_next_pages_dir_match_condition(pages_dir).await?,
             ModuleRuleCondition::any(vec![
                 ModuleRuleCondition::ResourcePathEndsWith(".js".to_string()),
                 ModuleRuleCondition::ResourcePathEndsWith(".jsx".to_string()),
@@ -113,6 +311,43 @@ async fn get_next_pages_transforms_rule(
     ))
 }
 
+/// A condition matching real Pages-router routes: files under `pages_dir`
+/// (including nested directories), excluding `pages/api` (API routes aren't pages), `_document`,
+/// and anything under `node_modules`.
+async fn get_next_pages_dir_match_condition(
+    pages_dir: FileSystemPathVc,
+) -> Result<ModuleRuleCondition> {
+    Ok(ModuleRuleCondition::all(vec![
+        ModuleRuleCondition::ResourcePathInExactDirectory(pages_dir.await?),
+        ModuleRuleCondition::not(ModuleRuleCondition::ResourcePathInExactDirectory(
+            pages_dir.join("api").await?,
+        )),
+        ModuleRuleCondition::not(ModuleRuleCondition::any(vec![
+            // TODO(alexkirsz): Possibly ignore _app as well?
+            ModuleRuleCondition::ResourcePathEquals(pages_dir.join("_document.js").await?),
+            ModuleRuleCondition::ResourcePathEquals(pages_dir.join("_document.jsx").await?),
+            ModuleRuleCondition::ResourcePathEquals(pages_dir.join("_document.ts").await?),
+            ModuleRuleCondition::ResourcePathEquals(pages_dir.join("_document.tsx").await?),
+        ])),
+        ModuleRuleCondition::not(ModuleRuleCondition::ResourcePathInDirectory(
+            pages_dir.root().join("node_modules").await?,
+        )),
+    ]))
+}
+
+/// A condition matching real App-router routes: files under `app_dir`,
+/// excluding anything under `node_modules`.
+async fn get_next_app_dir_match_condition(
+    app_dir: FileSystemPathVc,
+) -> Result<ModuleRuleCondition> {
+    Ok(ModuleRuleCondition::all(vec![
+        ModuleRuleCondition::ResourcePathInDirectory(app_dir.await?),
+        ModuleRuleCondition::not(ModuleRuleCondition::ResourcePathInDirectory(
+            app_dir.root().join("node_modules").await?,
+        )),
+    ]))
+}
+
 fn get_next_dynamic_transform_rule(
     is_development: bool,
     is_server: bool,
@@ -143,15 +378,24 @@ fn get_next_dynamic_transform_rule(
     )
 }
 
-fn get_next_font_transform_rule() -> ModuleRule {
+/// `page_match` restricts this to real routes (Pages or App), so importing
+/// `@next/font/*` from library code under `node_modules` or from
+/// `pages/api` doesn't trigger the font transform. `env` is threaded through
+/// so the font subsystem can consult `NEXT_FONT_GOOGLE_MOCKED_RESPONSES` and
+/// resolve fonts from a local mock instead of hitting Google Fonts, which
+/// keeps hermetic builds (and our own snapshot tests) reproducible.
+async fn get_next_font_transform_rule(
+    page_match: ModuleRuleCondition,
+    env: ProcessEnvVc,
+) -> Result<ModuleRule> {
     #[allow(unused_mut)] // This is mutated when next-font-local is enabled
     let mut font_loaders = vec!["@next/font/google".to_owned()];
     #[cfg(feature = "next-font-local")]
     font_loaders.push("@next/font/local".to_owned());
 
-    ModuleRule::new(
-        // TODO: Only match in pages (not pages/api), app/, etc.
+    Ok(ModuleRule::new(
         ModuleRuleCondition::all(vec![
+            page_match,
             ModuleRuleCondition::not(ModuleRuleCondition::ReferenceType(ReferenceType::Url(
                 UrlReferenceSubType::Undefined,
             ))),
@@ -163,9 +407,52 @@ fn get_next_font_transform_rule() -> ModuleRule {
             ]),
         ]),
         vec![ModuleRuleEffect::AddEcmascriptTransforms(
-            EcmascriptInputTransformsVc::cell(vec![EcmascriptInputTransform::NextJsFont(
-                StringsVc::cell(font_loaders),
-            )]),
+            EcmascriptInputTransformsVc::cell(vec![EcmascriptInputTransform::NextJsFont {
+                font_loaders: StringsVc::cell(font_loaders),
+                env,
+            }]),
+        )],
+    ))
+}
+
+/// Turns `export * from "..."` in a page into a compile error, so server-only
+/// exports re-exported from a shared module don't silently leak into the
+/// client bundle. Gated to page files via `page_match`.
+fn get_next_disallow_export_all_in_page_rule(page_match: ModuleRuleCondition) -> ModuleRule {
+    ModuleRule::new(
+        ModuleRuleCondition::all(vec![
+            page_match,
+            ModuleRuleCondition::any(vec![
+                ModuleRuleCondition::ResourcePathEndsWith(".js".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".jsx".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".ts".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".tsx".to_string()),
+            ]),
+        ]),
+        vec![ModuleRuleEffect::AddEcmascriptTransforms(
+            EcmascriptInputTransformsVc::cell(vec![
+                EcmascriptInputTransform::NextJsDisallowReExportAllInPage,
+            ]),
+        )],
+    )
+}
+
+/// Extracts a page's `config` export (e.g. `runtime`, `regions`) into module
+/// metadata so the rest of the pipeline can read it without evaluating the
+/// page itself. Gated to page files via `page_match`.
+fn get_next_page_config_rule(page_match: ModuleRuleCondition) -> ModuleRule {
+    ModuleRule::new(
+        ModuleRuleCondition::all(vec![
+            page_match,
+            ModuleRuleCondition::any(vec![
+                ModuleRuleCondition::ResourcePathEndsWith(".js".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".jsx".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".ts".to_string()),
+                ModuleRuleCondition::ResourcePathEndsWith(".tsx".to_string()),
+            ]),
+        ]),
+        vec![ModuleRuleEffect::AddEcmascriptTransforms(
+            EcmascriptInputTransformsVc::cell(vec![EcmascriptInputTransform::NextJsPageConfig]),
         )],
     )
 }